@@ -1,11 +1,13 @@
 use core::cell::{Cell, RefCell};
 use core::future::{Future, poll_fn};
-use core::task::{Poll, Waker};
+use core::task::{Context, Poll, Waker};
 
 use embassy_sync::waitqueue::WakerRegistration;
 
 use crate::consts::Ioctl;
 
+/// Number of IOCTLs that may be in flight at the same time.
+const SLOTS: usize = 4;
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum IoctlType {
@@ -19,6 +21,12 @@ pub enum IoctlType {
 pub enum IoctlError {
     /// Generic IOCTL failure with status code.
     Status(core::num::NonZeroI32),
+    /// The IOCTL was cancelled via `cancel_ioctl` before completing, whether
+    /// still pending or already handed to the runner, as distinct from a real
+    /// zero-length success.
+    Cancelled,
+    /// More scatter-gather regions were supplied than `MAX_REGIONS` supports.
+    TooManyRegions,
 }
 
 impl From<i32> for IoctlError {
@@ -35,93 +43,316 @@ impl From<IoctlError> for i32 {
     fn from(e: IoctlError) -> i32 {
         match e {
             IoctlError::Status(n) => n.get(),
+            // Not real chip status codes, just sentinels for local-only errors.
+            IoctlError::Cancelled => i32::MIN,
+            IoctlError::TooManyRegions => i32::MIN + 1,
         }
     }
 }
 
+/// Maximum number of regions in a single scatter-gather IOCTL.
+const MAX_REGIONS: usize = 4;
+
+/// Backing memory for an IOCTL response: either a single contiguous buffer, or
+/// a small ordered list of regions to be filled/drained in sequence.
+#[derive(Clone, Copy)]
+pub enum PendingIoctlInner {
+    Single(*mut [u8]),
+    Vectored([Option<*mut [u8]>; MAX_REGIONS]),
+}
+
 #[derive(Clone, Copy)]
 pub struct PendingIoctl {
-    pub buf: *mut [u8],
+    pub buf: PendingIoctlInner,
     pub kind: IoctlType,
     pub cmd: Ioctl,
     pub iface: u32,
+    /// Generation this request was issued under, carried through to `ioctl_done`
+    /// so a late, cancelled response can be told apart from a fresh one.
+    pub generation: u32,
+}
+
+/// A token identifying an in-flight IOCTL's slot, returned by `wait_pending` and
+/// required by `ioctl_done` to address the same slot.
+pub type IoctlToken = usize;
+
+/// What [`IoctlState::cancel_ioctl`] found in the slot, and therefore aborted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CancelResult {
+    /// The slot held no in-flight IOCTL with the given generation.
+    NotFound,
+    /// A `Pending` IOCTL was aborted before the runner ever saw it.
+    Pending,
+    /// A `Sent` IOCTL was aborted; the runner may still be mid-transfer.
+    Sent,
 }
 
 #[derive(Clone, Copy)]
-enum IoctlStateInner {
+enum SlotState {
+    Free,
     Pending(PendingIoctl),
-    Sent { buf: *mut [u8] },
+    Sent { buf: PendingIoctlInner, generation: u32 },
     Done { result: Result<usize, IoctlError> },
 }
 
-struct Wakers {
-    control: WakerRegistration,
-    runner: WakerRegistration,
+/// A fixed-capacity set of waker registrations, for places where more than one
+/// task may be waiting on the same condition. Plain `WakerRegistration` only
+/// retains the most recently registered waker, so sharing a single one across
+/// independent waiters silently drops all but the last (see `Slot::control`,
+/// which instead gives each in-flight IOCTL its own registration).
+///
+/// Beyond `N` concurrent waiters, registration falls back to waking and
+/// evicting an existing entry rather than dropping the new registration, so
+/// the evicted waiter gets a spurious wakeup and a chance to re-register
+/// instead of stalling forever.
+struct WakerSet<const N: usize> {
+    wakers: [RefCell<Option<Waker>>; N],
+}
+
+impl<const N: usize> WakerSet<N> {
+    const fn new() -> Self {
+        Self {
+            wakers: [const { RefCell::new(None) }; N],
+        }
+    }
+
+    /// Register `waker` into a slot already registered to an equivalent
+    /// waker, or else a free one. If all `N` slots are held by distinct
+    /// wakers, slot 0's occupant is woken before being evicted, so that
+    /// waiter gets a chance to re-register instead of stalling forever.
+    fn register(&self, waker: &Waker) {
+        for slot in &self.wakers {
+            let mut slot = slot.borrow_mut();
+            if slot.as_ref().map_or(true, |w| w.will_wake(waker)) {
+                *slot = Some(waker.clone());
+                return;
+            }
+        }
+        if let Some(evicted) = self.wakers[0].borrow_mut().replace(waker.clone()) {
+            evicted.wake();
+        }
+    }
+
+    fn wake(&self) {
+        for slot in &self.wakers {
+            if let Some(waker) = slot.borrow_mut().take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+struct Slot {
+    state: Cell<SlotState>,
+    control: RefCell<WakerRegistration>,
 }
 
-impl Wakers {
+impl Slot {
     const fn new() -> Self {
         Self {
-            control: WakerRegistration::new(),
-            runner: WakerRegistration::new(),
+            state: Cell::new(SlotState::Free),
+            control: RefCell::new(WakerRegistration::new()),
+        }
+    }
+}
+
+/// RAII guard that releases an IOCTL's slot if the future holding it is
+/// dropped before reaching completion (e.g. a losing `select!` branch),
+/// instead of leaking the slot forever.
+struct IoctlGuard<'a> {
+    state: &'a IoctlState,
+    token: Option<(IoctlToken, u32)>,
+}
+
+impl Drop for IoctlGuard<'_> {
+    fn drop(&mut self) {
+        if let Some((token, generation)) = self.token {
+            self.state.cancel_ioctl(token, generation);
         }
     }
 }
 
 pub struct IoctlState {
-    state: Cell<IoctlStateInner>,
-    wakers: RefCell<Wakers>,
+    slots: [Slot; SLOTS],
+    runner: RefCell<WakerRegistration>,
+    full: WakerSet<SLOTS>,
+    generation: Cell<u32>,
 }
 
 impl IoctlState {
     pub const fn new() -> Self {
         Self {
-            state: Cell::new(IoctlStateInner::Done { result: Ok(0) }),
-            wakers: RefCell::new(Wakers::new()),
+            slots: [const { Slot::new() }; SLOTS],
+            runner: RefCell::new(WakerRegistration::new()),
+            full: WakerSet::new(),
+            generation: Cell::new(0),
         }
     }
 
-    fn wake_control(&self) {
-        self.wakers.borrow_mut().control.wake();
+    /// Bump and return the generation counter, invalidating any previously
+    /// issued `Sent` response for the same slot.
+    fn next_generation(&self) -> u32 {
+        let gen = self.generation.get().wrapping_add(1);
+        self.generation.set(gen);
+        gen
     }
 
-    fn register_control(&self, waker: &Waker) {
-        self.wakers.borrow_mut().control.register(waker);
+    fn wake_control(&self, token: IoctlToken) {
+        self.slots[token].control.borrow_mut().wake();
+    }
+
+    fn register_control(&self, token: IoctlToken, waker: &Waker) {
+        self.slots[token].control.borrow_mut().register(waker);
     }
 
     fn wake_runner(&self) {
-        self.wakers.borrow_mut().runner.wake();
+        self.runner.borrow_mut().wake();
     }
 
     fn register_runner(&self, waker: &Waker) {
-        self.wakers.borrow_mut().runner.register(waker);
+        self.runner.borrow_mut().register(waker);
     }
 
-    pub fn wait_complete(&self) -> impl Future<Output = Result<usize, IoctlError>> + '_ {
-        poll_fn(|cx| {
-            if let IoctlStateInner::Done { result } = self.state.get() {
+    fn wake_full(&self) {
+        self.full.wake();
+    }
+
+    fn register_full(&self, waker: &Waker) {
+        self.full.register(waker);
+    }
+
+    fn claim_slot(&self, pending: PendingIoctl, cx: &mut Context<'_>) -> Poll<IoctlToken> {
+        for (token, slot) in self.slots.iter().enumerate() {
+            // A `Done` slot is also claimable: its result is only ever read once, by
+            // the caller that owns its token, so an unread one (e.g. cancelled then
+            // dropped without a final poll) would otherwise leak the slot forever.
+            if matches!(slot.state.get(), SlotState::Free | SlotState::Done { .. }) {
+                slot.state.set(SlotState::Pending(pending));
+                return Poll::Ready(token);
+            }
+        }
+        self.register_full(cx.waker());
+        Poll::Pending
+    }
+
+    fn wait_complete(&self, token: IoctlToken) -> impl Future<Output = Result<usize, IoctlError>> + '_ {
+        poll_fn(move |cx| {
+            if let SlotState::Done { result } = self.slots[token].state.get() {
                 Poll::Ready(result)
             } else {
-                self.register_control(cx.waker());
+                self.register_control(token, cx.waker());
                 Poll::Pending
             }
         })
     }
 
-    pub fn wait_pending(&self) -> impl Future<Output = PendingIoctl> + '_ {
+    /// Wait for the oldest slot still `Pending`, mark it `Sent` and hand it to
+    /// the runner. "Oldest" is by generation rather than slot index, since a
+    /// freed low-index slot can be reused by a brand-new request while a
+    /// genuinely older one is still waiting in a higher-index slot.
+    ///
+    /// Ages are compared relative to the current generation (via wrapping
+    /// subtraction) rather than directly, so a request issued just before the
+    /// counter wraps still reads as older than one issued just after — this
+    /// guarantee only breaks if more than `u32::MAX / 2` IOCTLs are issued
+    /// while a single one stays `Pending`, which isn't reachable with `SLOTS`
+    /// concurrent requests.
+    pub fn wait_pending(&self) -> impl Future<Output = (IoctlToken, PendingIoctl)> + '_ {
         poll_fn(|cx| {
-            if let IoctlStateInner::Pending(pending) = self.state.get() {
-                self.state.set(IoctlStateInner::Sent { buf: pending.buf });
-                Poll::Ready(pending)
-            } else {
-                self.register_runner(cx.waker());
-                Poll::Pending
+            let now = self.generation.get();
+            let oldest = self
+                .slots
+                .iter()
+                .enumerate()
+                .filter_map(|(token, slot)| match slot.state.get() {
+                    SlotState::Pending(pending) => Some((token, pending)),
+                    _ => None,
+                })
+                .max_by_key(|(_, pending)| now.wrapping_sub(pending.generation));
+
+            if let Some((token, pending)) = oldest {
+                self.slots[token].state.set(SlotState::Sent {
+                    buf: pending.buf,
+                    generation: pending.generation,
+                });
+                return Poll::Ready((token, pending));
             }
+            self.register_runner(cx.waker());
+            Poll::Pending
         })
     }
 
-    pub fn cancel_ioctl(&self) {
-        self.state.set(IoctlStateInner::Done { result: Ok(0) });
+    /// Cancel the request occupying `token`, if `generation` still matches
+    /// what's there. A generation mismatch means the slot was already freed
+    /// and reused for something else, so it's reported as `NotFound` rather
+    /// than cancelling an unrelated request.
+    pub fn cancel_ioctl(&self, token: IoctlToken, generation: u32) -> CancelResult {
+        let (outcome, result) = match self.slots[token].state.get() {
+            SlotState::Pending(pending) if pending.generation == generation => {
+                (CancelResult::Pending, Err(IoctlError::Cancelled))
+            },
+            SlotState::Sent { generation: sent_generation, .. } if sent_generation == generation => {
+                (CancelResult::Sent, Err(IoctlError::Cancelled))
+            },
+            _ => return CancelResult::NotFound,
+        };
+        self.next_generation();
+        self.slots[token].state.set(SlotState::Done { result });
+        self.wake_control(token);
+        outcome
+    }
+
+    /// Poll-based equivalent of [`Self::do_ioctl`], for embedding this IOCTL's
+    /// state machine inside a hand-written `Future` or `select!` instead of
+    /// spawning a task. `token` must start as `None` and be threaded back in
+    /// unchanged on every poll; it is reset to `None` once this resolves.
+    ///
+    /// If the caller abandons this by dropping its future instead of polling
+    /// it to completion (e.g. a losing `select!` branch), `token` is left
+    /// `Some` and the occupied slot must be released with
+    /// [`Self::cancel_ioctl`] — see [`Self::do_ioctl`] for an example that
+    /// does this via an RAII guard.
+    pub fn poll_ioctl(
+        &self,
+        token: &mut Option<(IoctlToken, u32)>,
+        kind: IoctlType,
+        cmd: Ioctl,
+        iface: u32,
+        buf: &mut [u8],
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<usize, IoctlError>> {
+        let (tok, _generation) = match *token {
+            Some(tok) => tok,
+            None => {
+                let generation = self.next_generation();
+                let pending = PendingIoctl {
+                    buf: PendingIoctlInner::Single(buf),
+                    kind,
+                    cmd,
+                    iface,
+                    generation,
+                };
+                match self.claim_slot(pending, cx) {
+                    Poll::Ready(tok) => {
+                        *token = Some((tok, generation));
+                        self.wake_runner();
+                        (tok, generation)
+                    },
+                    Poll::Pending => return Poll::Pending,
+                }
+            },
+        };
+
+        if let SlotState::Done { result } = self.slots[tok].state.get() {
+            self.slots[tok].state.set(SlotState::Free);
+            self.wake_full();
+            *token = None;
+            Poll::Ready(result)
+        } else {
+            self.register_control(tok, cx.waker());
+            Poll::Pending
+        }
     }
 
     pub async fn do_ioctl(
@@ -131,30 +362,153 @@ impl IoctlState {
         iface: u32,
         buf: &mut [u8],
     ) -> Result<usize, IoctlError> {
-        self.state
-            .set(IoctlStateInner::Pending(PendingIoctl { buf, kind, cmd, iface }));
+        let mut guard = IoctlGuard { state: self, token: None };
+        poll_fn(|cx| self.poll_ioctl(&mut guard.token, kind, cmd, iface, &mut *buf, cx)).await
+    }
+
+    /// Like [`Self::do_ioctl`], but scatters the response across `regions` in
+    /// order instead of requiring one contiguous buffer.
+    ///
+    /// Returns [`IoctlError::TooManyRegions`] if `regions` has more than
+    /// `MAX_REGIONS` entries, rather than silently copying into only the
+    /// first few and under-reporting `written`.
+    pub async fn do_ioctl_vectored(
+        &self,
+        kind: IoctlType,
+        cmd: Ioctl,
+        iface: u32,
+        regions: &mut [&mut [u8]],
+    ) -> Result<usize, IoctlError> {
+        if regions.len() > MAX_REGIONS {
+            return Err(IoctlError::TooManyRegions);
+        }
+
+        let mut list = [None; MAX_REGIONS];
+        for (slot, region) in list.iter_mut().zip(regions.iter_mut()) {
+            *slot = Some(&mut **region as *mut [u8]);
+        }
+
+        let generation = self.next_generation();
+        let pending = PendingIoctl {
+            buf: PendingIoctlInner::Vectored(list),
+            kind,
+            cmd,
+            iface,
+            generation,
+        };
+
+        let mut guard = IoctlGuard { state: self, token: None };
+        let token = poll_fn(|cx| self.claim_slot(pending, cx)).await;
+        guard.token = Some((token, generation));
         self.wake_runner();
-        self.wait_complete().await
+        let result = self.wait_complete(token).await;
+        self.slots[token].state.set(SlotState::Free);
+        self.wake_full();
+        guard.token = None;
+        result
     }
 
-    pub fn ioctl_done(&self, response: &[u8], result: Result<(), IoctlError>) {
-        if let IoctlStateInner::Sent { buf } = self.state.get() {
-            // Check that the buffer is valid!
-            let buf = unsafe { &mut *buf };
+    pub fn ioctl_done(
+        &self,
+        token: IoctlToken,
+        generation: u32,
+        response: &[u8],
+        result: Result<(), IoctlError>,
+    ) {
+        if let SlotState::Sent { buf, generation: sent_generation } = self.slots[token].state.get() {
+            if sent_generation != generation {
+                debug!("Discarding stale IOCTL response for a cancelled request");
+                return;
+            }
 
             let result = match result {
-                Ok(()) => {
-                    let len = core::cmp::min(buf.len(), response.len());
-                    buf[..len].copy_from_slice(&response[..len]);
-                    Ok(len)
-                },
+                Ok(()) => Ok(Self::copy_into(buf, response)),
                 Err(e) => Err(e),
             };
 
-            self.state.set(IoctlStateInner::Done { result });
-            self.wake_control();
+            self.slots[token].state.set(SlotState::Done { result });
+            self.wake_control(token);
         } else {
             warn!("IOCTL Response but no pending Ioctl");
         }
     }
+
+    /// Copy `response` into one contiguous buffer, or sequentially across a
+    /// region list until either is exhausted. Returns the total bytes written.
+    fn copy_into(buf: PendingIoctlInner, response: &[u8]) -> usize {
+        match buf {
+            PendingIoctlInner::Single(region) => {
+                // Check that the buffer is valid!
+                let region = unsafe { &mut *region };
+                let len = core::cmp::min(region.len(), response.len());
+                region[..len].copy_from_slice(&response[..len]);
+                len
+            },
+            PendingIoctlInner::Vectored(regions) => {
+                let mut remaining = response;
+                let mut written = 0;
+                for region in regions.into_iter().flatten() {
+                    if remaining.is_empty() {
+                        break;
+                    }
+                    // Check that the buffer is valid!
+                    let region = unsafe { &mut *region };
+                    let len = core::cmp::min(region.len(), remaining.len());
+                    region[..len].copy_from_slice(&remaining[..len]);
+                    written += len;
+                    remaining = &remaining[len..];
+                }
+                written
+            },
+        }
+    }
+}
+
+/// Bitmask of asynchronous chip event classes (link up/down, scan results, ...).
+pub type EventMask = u32;
+
+/// Number of `await_events` subscriber wakers held at once (not a hard cap;
+/// see `EventState`).
+const MAX_EVENT_SUBSCRIBERS: usize = 4;
+
+/// Readiness state for chip events that arrive independently of any IOCTL
+/// request/response, modeled on tokio's `ScheduledIo`: a bitmask of event
+/// classes the runner has posted, plus a waker registration per subscriber
+/// task waiting on them. `MAX_EVENT_SUBSCRIBERS` isn't an enforced cap — a
+/// 5th concurrent subscriber is still woken (via `WakerSet::register`'s
+/// evict-and-wake fallback) rather than silently losing its wakeup.
+pub struct EventState {
+    pending: Cell<EventMask>,
+    wakers: WakerSet<MAX_EVENT_SUBSCRIBERS>,
+}
+
+impl EventState {
+    pub const fn new() -> Self {
+        Self {
+            pending: Cell::new(0),
+            wakers: WakerSet::new(),
+        }
+    }
+
+    /// Called by the runner when the chip reports an event. ORs `class` into
+    /// the pending bitmask and wakes every waiting subscriber.
+    pub fn post_event(&self, class: EventMask) {
+        self.pending.set(self.pending.get() | class);
+        self.wakers.wake();
+    }
+
+    /// Wait for any event class in `interest` to become pending, returning the
+    /// ready bits intersecting `interest` and clearing them.
+    pub fn await_events(&self, interest: EventMask) -> impl Future<Output = EventMask> + '_ {
+        poll_fn(move |cx| {
+            let ready = self.pending.get() & interest;
+            if ready != 0 {
+                self.pending.set(self.pending.get() & !ready);
+                Poll::Ready(ready)
+            } else {
+                self.wakers.register(cx.waker());
+                Poll::Pending
+            }
+        })
+    }
 }